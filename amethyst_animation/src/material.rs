@@ -1,7 +1,10 @@
+use std::collections::BTreeSet;
+
 use fnv::FnvHashMap;
 
-use amethyst_assets::Handle;
-use amethyst_core::specs::Fetch;
+use amethyst_assets::{Handle, ReloadedEvent};
+use amethyst_core::shrev::{EventChannel, ReaderId};
+use amethyst_core::specs::{Fetch, FetchMut, Resources, System};
 use amethyst_renderer::{Material, Texture, TextureOffset};
 use minterpolate::InterpolationPrimitive;
 
@@ -12,6 +15,33 @@ use {AnimationSampling, ApplyData, BlendMethod};
 pub struct MaterialTextureSet {
     textures: FnvHashMap<usize, Handle<Texture>>,
     texture_inverse: FnvHashMap<Handle<Texture>, usize>,
+    /// Sticky copy of `texture_inverse`: entries are added whenever a handle is
+    /// bound, and survive a reload rebind (`insert` displacing the handle from
+    /// `textures`/`texture_inverse` to make room for its reloaded replacement at the
+    /// same index). This lets `index_or_last_known` answer with the last known-good
+    /// index for a handle that is momentarily unmapped mid hot-reload instead of
+    /// reporting nothing at all. `remove` prunes the entry, since at that point the
+    /// handle is gone for good rather than mid-reload, and leaving it in place would
+    /// let a later `allocate`/`insert` hand the freed index to an unrelated texture
+    /// while this map kept pointing stale lookups at it.
+    last_known_index: FnvHashMap<Handle<Texture>, usize>,
+    /// Every handle that has ever had a `last_known_index` entry pointing at a given
+    /// index, including ones displaced by a later reload rebind. `remove` uses this to
+    /// prune *all* of an index's `last_known_index` entries, not just the handle
+    /// currently occupying it; without it, a handle displaced by `insert` before the
+    /// index was ever `remove`d would be missed and could later resolve to whatever
+    /// unrelated handle reuses that index.
+    last_known_index_holders: FnvHashMap<usize, Vec<Handle<Texture>>>,
+    /// Indices below `next_index` that are neither bound in `textures` nor
+    /// outstanding in `reserved`.
+    free_indices: BTreeSet<usize>,
+    /// Indices returned by `reserve` that have not yet been bound with `insert`.
+    /// Tracked so an abandoned reservation can still be reclaimed by `remove`.
+    reserved: BTreeSet<usize>,
+    /// One past the highest index ever handed out by `allocate`/`reserve` or passed
+    /// to `insert`; every index below it is accounted for by `textures`,
+    /// `free_indices` or `reserved`.
+    next_index: usize,
 }
 
 impl MaterialTextureSet {
@@ -19,6 +49,11 @@ impl MaterialTextureSet {
         MaterialTextureSet {
             textures: FnvHashMap::default(),
             texture_inverse: FnvHashMap::default(),
+            last_known_index: FnvHashMap::default(),
+            last_known_index_holders: FnvHashMap::default(),
+            free_indices: BTreeSet::default(),
+            reserved: BTreeSet::default(),
+            next_index: 0,
         }
     }
 
@@ -30,58 +65,304 @@ impl MaterialTextureSet {
         self.texture_inverse.get(handle).cloned()
     }
 
+    /// Like `index`, but falls back to the last index `handle` was successfully
+    /// bound to if it is not currently mapped, instead of returning `None`. Once that
+    /// index has been `remove`d the fallback is gone and this returns `None`. Used by
+    /// animation sampling to avoid reporting an arbitrary, unrelated index during the
+    /// momentary gap while a texture hot-reload is in flight.
+    pub fn index_or_last_known(&self, handle: &Handle<Texture>) -> Option<usize> {
+        self.texture_inverse
+            .get(handle)
+            .or_else(|| self.last_known_index.get(handle))
+            .cloned()
+    }
+
+    /// Insert `handle` at `index`, overwriting whatever was there.
+    ///
+    /// If `handle` was already mapped to a different index, that stale forward mapping
+    /// is removed first (and freed for reuse) so `index` and `texture_inverse` can
+    /// never point at each other inconsistently. `index` is also reconciled against
+    /// the auto-indexing bookkeeping, so a manually-chosen index can never later be
+    /// silently reused and clobbered by `allocate`/`reserve`.
     pub fn insert(&mut self, index: usize, handle: Handle<Texture>) {
-        self.textures.insert(index, handle.clone());
+        if index >= self.next_index {
+            self.free_indices
+                .extend(self.next_index..index);
+            self.next_index = index + 1;
+        }
+        self.free_indices.remove(&index);
+        self.reserved.remove(&index);
+
+        if let Some(&old_index) = self.texture_inverse.get(&handle) {
+            if old_index != index {
+                self.textures.remove(&old_index);
+                self.free_indices.insert(old_index);
+            }
+        }
+        if let Some(old_handle) = self.textures.insert(index, handle.clone()) {
+            self.texture_inverse.remove(&old_handle);
+        }
+        self.last_known_index.insert(handle.clone(), index);
+        let holders = self.last_known_index_holders.entry(index).or_insert_with(Vec::new);
+        if !holders.contains(&handle) {
+            holders.push(handle.clone());
+        }
         self.texture_inverse.insert(handle, index);
     }
 
+    /// Reserve the lowest free index without binding a handle to it yet. The index
+    /// is tracked as outstanding until it is either bound with `insert` or released
+    /// with `remove`.
+    pub fn reserve(&mut self) -> usize {
+        let index = self.next_free_index();
+        self.reserved.insert(index);
+        index
+    }
+
+    /// Assign `handle` to the lowest free index (reusing indices freed by `remove`)
+    /// and return that index.
+    pub fn allocate(&mut self, handle: Handle<Texture>) -> usize {
+        let index = self.next_free_index();
+        self.insert(index, handle);
+        index
+    }
+
+    fn next_free_index(&mut self) -> usize {
+        if let Some(&index) = self.free_indices.iter().next() {
+            self.free_indices.remove(&index);
+            index
+        } else {
+            let index = self.next_index;
+            self.next_index += 1;
+            index
+        }
+    }
+
     pub fn remove(&mut self, index: usize) {
         if let Some(handle) = self.textures.remove(&index) {
             self.texture_inverse.remove(&handle);
+            self.free_indices.insert(index);
+
+            // `index` is gone for good now, so drop every `last_known_index` entry
+            // that still points at it: not just `handle`'s, but also any earlier
+            // occupant displaced by a reload rebind (`insert` overwriting `index`
+            // without going through `remove`), which keeps its sticky entry until
+            // `index` is actually freed. A guard on the current value is needed
+            // because a holder may since have been `insert`ed at a *different*
+            // index, in which case its `last_known_index` entry is current and must
+            // not be pruned here.
+            if let Some(holders) = self.last_known_index_holders.remove(&index) {
+                for holder in holders {
+                    if self.last_known_index.get(&holder) == Some(&index) {
+                        self.last_known_index.remove(&holder);
+                    }
+                }
+            }
+        } else if self.reserved.remove(&index) {
+            // Reclaim an abandoned reservation so it can be handed out again.
+            self.free_indices.insert(index);
         }
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (&usize, &Handle<Texture>)> {
+        self.textures.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.textures.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.textures.is_empty()
+    }
+
     pub fn clear(&mut self) {
         self.textures.clear();
         self.texture_inverse.clear();
+        self.last_known_index.clear();
+        self.last_known_index_holders.clear();
+        self.free_indices.clear();
+        self.reserved.clear();
+        self.next_index = 0;
+    }
+}
+
+/// Describes how a sprite-sheet/atlas texture is divided into animatable frames.
+#[derive(Debug, Clone)]
+pub enum AtlasGrid {
+    /// A uniform grid of `columns` by `rows` frames, numbered row-major starting at
+    /// the top-left.
+    Grid { columns: usize, rows: usize },
+    /// Explicit UV sub-rectangles, one per frame.
+    Rects(Vec<TextureOffset>),
+}
+
+impl AtlasGrid {
+    fn offset(&self, frame: usize) -> TextureOffset {
+        match *self {
+            AtlasGrid::Grid { columns, rows } => {
+                let columns = columns.max(1);
+                let rows = rows.max(1);
+                let col = (frame % columns) as f32;
+                let row = ((frame / columns) % rows) as f32;
+                texture_offset(
+                    (col / columns as f32, (col + 1.0) / columns as f32),
+                    (row / rows as f32, (row + 1.0) / rows as f32),
+                )
+            }
+            AtlasGrid::Rects(ref rects) => {
+                if rects.is_empty() {
+                    // No frames to show; fall back to the identity rectangle rather
+                    // than indexing into an empty `Vec`.
+                    texture_offset((0.0, 1.0), (0.0, 1.0))
+                } else {
+                    rects[frame % rects.len()].clone()
+                }
+            }
+        }
+    }
+
+    /// Find the frame whose offset is closest to `offset`, used to reverse-map a
+    /// sampled `TextureOffset` back to a frame index.
+    fn nearest_frame(&self, offset: &TextureOffset) -> usize {
+        match *self {
+            AtlasGrid::Grid { columns, rows } => {
+                let columns = columns.max(1);
+                let rows = rows.max(1);
+                let col = (offset.u.0 * columns as f32).round().max(0.0) as usize;
+                let row = (offset.v.0 * rows as f32).round().max(0.0) as usize;
+                row.min(rows - 1) * columns + col.min(columns - 1)
+            }
+            AtlasGrid::Rects(ref rects) => rects
+                .iter()
+                .enumerate()
+                .min_by(|&(_, a), &(_, b)| {
+                    let da = (a.u.0 - offset.u.0).powi(2) + (a.v.0 - offset.v.0).powi(2);
+                    let db = (b.u.0 - offset.u.0).powi(2) + (b.v.0 - offset.v.0).powi(2);
+                    da.partial_cmp(&db).unwrap()
+                })
+                .map(|(i, _)| i)
+                .unwrap_or(0),
+        }
+    }
+}
+
+/// Atlas grid descriptions for sprite-sheet textures, keyed the same way
+/// `MaterialTextureSet` keys its texture handles.
+#[derive(Debug, Default)]
+pub struct MaterialTextureAtlasSet {
+    atlases: FnvHashMap<usize, AtlasGrid>,
+}
+
+impl MaterialTextureAtlasSet {
+    pub fn new() -> Self {
+        MaterialTextureAtlasSet {
+            atlases: FnvHashMap::default(),
+        }
+    }
+
+    pub fn insert(&mut self, index: usize, atlas: AtlasGrid) {
+        self.atlases.insert(index, atlas);
+    }
+
+    pub fn remove(&mut self, index: usize) {
+        self.atlases.remove(&index);
+    }
+
+    pub fn get(&self, index: usize) -> Option<&AtlasGrid> {
+        self.atlases.get(&index)
     }
 }
 
 /// Sampler primitive for Material animations
-/// Note that material can only ever be animated with `Step`, or a panic will occur.
+///
+/// `Texture` can only ever be animated with `Step`, since texture indices can't be
+/// blended together; `Offset` supports full interpolation, so it can additionally be
+/// `Linear`- or spline-sampled.
 #[derive(Debug, Clone, Copy, Serialize, Deserialize)]
 pub enum MaterialPrimitive {
     Texture(usize),
     Offset((f32, f32), (f32, f32)),
+    AtlasFrame(usize),
 }
 
 impl InterpolationPrimitive for MaterialPrimitive {
-    fn add(&self, _: &Self) -> Self {
-        panic!("Cannot add MaterialPrimitive")
+    fn add(&self, other: &Self) -> Self {
+        match (*self, *other) {
+            (MaterialPrimitive::Offset(u0, v0), MaterialPrimitive::Offset(u1, v1)) => {
+                MaterialPrimitive::Offset(
+                    (u0.0 + u1.0, u0.1 + u1.1),
+                    (v0.0 + v1.0, v0.1 + v1.1),
+                )
+            }
+            _ => panic!("Cannot add a non-interpolable MaterialPrimitive variant"),
+        }
     }
 
-    fn sub(&self, _: &Self) -> Self {
-        panic!("Cannot sub MaterialPrimitive")
+    fn sub(&self, other: &Self) -> Self {
+        match (*self, *other) {
+            (MaterialPrimitive::Offset(u0, v0), MaterialPrimitive::Offset(u1, v1)) => {
+                MaterialPrimitive::Offset(
+                    (u0.0 - u1.0, u0.1 - u1.1),
+                    (v0.0 - v1.0, v0.1 - v1.1),
+                )
+            }
+            _ => panic!("Cannot sub a non-interpolable MaterialPrimitive variant"),
+        }
     }
 
-    fn mul(&self, _: f32) -> Self {
-        panic!("Cannot mul MaterialPrimitive")
+    fn mul(&self, scalar: f32) -> Self {
+        match *self {
+            MaterialPrimitive::Offset(u, v) => {
+                MaterialPrimitive::Offset((u.0 * scalar, u.1 * scalar), (v.0 * scalar, v.1 * scalar))
+            }
+            MaterialPrimitive::Texture(_) | MaterialPrimitive::AtlasFrame(_) => {
+                panic!("Cannot mul a non-interpolable MaterialPrimitive variant")
+            }
+        }
     }
 
-    fn dot(&self, _: &Self) -> f32 {
-        panic!("Cannot dot MaterialPrimitive")
+    fn dot(&self, other: &Self) -> f32 {
+        match (*self, *other) {
+            (MaterialPrimitive::Offset(u0, v0), MaterialPrimitive::Offset(u1, v1)) => {
+                u0.0 * u1.0 + u0.1 * u1.1 + v0.0 * v1.0 + v0.1 * v1.1
+            }
+            _ => panic!("Cannot dot a non-interpolable MaterialPrimitive variant"),
+        }
     }
 
     fn magnitude2(&self) -> f32 {
-        panic!("Cannot magnitude2 MaterialPrimitive")
+        match *self {
+            MaterialPrimitive::Offset(u, v) => u.0 * u.0 + u.1 * u.1 + v.0 * v.0 + v.1 * v.1,
+            MaterialPrimitive::Texture(_) | MaterialPrimitive::AtlasFrame(_) => {
+                panic!("Cannot magnitude2 a non-interpolable MaterialPrimitive variant")
+            }
+        }
     }
 
     fn magnitude(&self) -> f32 {
-        panic!("Cannot magnitude MaterialPrimitive")
+        match *self {
+            MaterialPrimitive::Offset(..) => self.magnitude2().sqrt(),
+            MaterialPrimitive::Texture(_) | MaterialPrimitive::AtlasFrame(_) => {
+                panic!("Cannot magnitude a non-interpolable MaterialPrimitive variant")
+            }
+        }
     }
 
     fn normalize(&self) -> Self {
-        panic!("Cannot normalize MaterialPrimitive")
+        match *self {
+            MaterialPrimitive::Offset(u, v) => {
+                let mag = self.magnitude();
+                if mag.abs() < 1e-8 {
+                    *self
+                } else {
+                    MaterialPrimitive::Offset((u.0 / mag, u.1 / mag), (v.0 / mag, v.1 / mag))
+                }
+            }
+            MaterialPrimitive::Texture(_) | MaterialPrimitive::AtlasFrame(_) => {
+                panic!("Cannot normalize a non-interpolable MaterialPrimitive variant")
+            }
+        }
     }
 }
 
@@ -102,10 +383,14 @@ pub enum MaterialChannel {
     AmbientOcclusionOffset,
     CaveatTexture,
     CaveatOffset,
+    AlbedoAtlasFrame,
+    // There are no channels for PBR scalar factors (metallic, roughness, emission
+    // strength, ambient occlusion strength) or an albedo tint: only textures, texture
+    // offsets and the albedo atlas frame are animatable.
 }
 
 impl<'a> ApplyData<'a> for Material {
-    type ApplyData = Fetch<'a, MaterialTextureSet>;
+    type ApplyData = (Fetch<'a, MaterialTextureSet>, Fetch<'a, MaterialTextureAtlasSet>);
 }
 
 fn offset(offset: &TextureOffset) -> MaterialPrimitive {
@@ -124,41 +409,47 @@ impl AnimationSampling for Material {
         &mut self,
         channel: &Self::Channel,
         data: &Self::Primitive,
-        extra: &Fetch<MaterialTextureSet>,
+        extra: &(Fetch<MaterialTextureSet>, Fetch<MaterialTextureAtlasSet>),
     ) {
+        // `Texture` and `AtlasFrame` indices cannot be interpolated: a `Linear`- or
+        // spline-sampled animation driving one of those channels never reaches this
+        // method in the first place, because `minterpolate::interpolate` calls
+        // `add`/`mul`/etc. on the sampled primitives first, and those already panic
+        // for `MaterialPrimitive::Texture`/`AtlasFrame` (see `InterpolationPrimitive`
+        // above). There's nothing left for `apply_sample` itself to check here.
         match (*channel, *data) {
             (MaterialChannel::AlbedoTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.albedo = handle;
                 }
             }
             (MaterialChannel::EmissionTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.emission = handle;
                 }
             }
             (MaterialChannel::NormalTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.normal = handle;
                 }
             }
             (MaterialChannel::MetallicTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.metallic = handle;
                 }
             }
             (MaterialChannel::RoughnessTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.roughness = handle;
                 }
             }
             (MaterialChannel::AmbientOcclusionTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.ambient_occlusion = handle;
                 }
             }
             (MaterialChannel::CaveatTexture, MaterialPrimitive::Texture(i)) => {
-                if let Some(handle) = extra.handle(i) {
+                if let Some(handle) = extra.0.handle(i) {
                     self.caveat = handle;
                 }
             }
@@ -185,36 +476,56 @@ impl AnimationSampling for Material {
                 self.caveat_offset = texture_offset(u, v)
             }
 
+            (MaterialChannel::AlbedoAtlasFrame, MaterialPrimitive::AtlasFrame(frame)) => {
+                // Use the same hot-reload-tolerant lookup as `current_sample`, so a
+                // frame sample applied mid-reload isn't silently dropped during the
+                // momentary gap `index_or_last_known` exists to smooth over.
+                if let Some(index) = extra.0.index_or_last_known(&self.albedo) {
+                    if let Some(atlas) = extra.1.get(index) {
+                        self.albedo_offset = atlas.offset(frame);
+                    }
+                }
+            }
+
             _ => panic!("Bad combination of data in Material animation"),
         }
     }
 
+    // A texture handle can momentarily fail to resolve to an index while a hot
+    // reload is in flight (see `MaterialTextureSetReloadSystem`). `index_or_last_known`
+    // reports the last index the handle successfully resolved to in that case, so
+    // this returns the previously sampled primitive instead of an arbitrary,
+    // unrelated one; `0` is only used as a last resort for a handle that has no
+    // current or last-known index (it was never bound, or its index was `remove`d).
     fn current_sample(
         &self,
         channel: &Self::Channel,
-        extra: &Fetch<MaterialTextureSet>,
+        extra: &(Fetch<MaterialTextureSet>, Fetch<MaterialTextureAtlasSet>),
     ) -> Self::Primitive {
         match *channel {
             MaterialChannel::AlbedoTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.albedo).unwrap())
-            }
-            MaterialChannel::EmissionTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.emission).unwrap())
+                MaterialPrimitive::Texture(extra.0.index_or_last_known(&self.albedo).unwrap_or(0))
             }
+            MaterialChannel::EmissionTexture => MaterialPrimitive::Texture(
+                extra.0.index_or_last_known(&self.emission).unwrap_or(0),
+            ),
             MaterialChannel::NormalTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.normal).unwrap())
-            }
-            MaterialChannel::MetallicTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.metallic).unwrap())
-            }
-            MaterialChannel::RoughnessTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.roughness).unwrap())
-            }
-            MaterialChannel::AmbientOcclusionTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.ambient_occlusion).unwrap())
+                MaterialPrimitive::Texture(extra.0.index_or_last_known(&self.normal).unwrap_or(0))
             }
+            MaterialChannel::MetallicTexture => MaterialPrimitive::Texture(
+                extra.0.index_or_last_known(&self.metallic).unwrap_or(0),
+            ),
+            MaterialChannel::RoughnessTexture => MaterialPrimitive::Texture(
+                extra.0.index_or_last_known(&self.roughness).unwrap_or(0),
+            ),
+            MaterialChannel::AmbientOcclusionTexture => MaterialPrimitive::Texture(
+                extra
+                    .0
+                    .index_or_last_known(&self.ambient_occlusion)
+                    .unwrap_or(0),
+            ),
             MaterialChannel::CaveatTexture => {
-                MaterialPrimitive::Texture(extra.index(&self.caveat).unwrap())
+                MaterialPrimitive::Texture(extra.0.index_or_last_known(&self.caveat).unwrap_or(0))
             }
             MaterialChannel::AlbedoOffset => offset(&self.albedo_offset),
             MaterialChannel::EmissionOffset => offset(&self.emission_offset),
@@ -223,6 +534,15 @@ impl AnimationSampling for Material {
             MaterialChannel::RoughnessOffset => offset(&self.roughness_offset),
             MaterialChannel::AmbientOcclusionOffset => offset(&self.ambient_occlusion_offset),
             MaterialChannel::CaveatOffset => offset(&self.caveat_offset),
+            MaterialChannel::AlbedoAtlasFrame => {
+                let frame = extra
+                    .0
+                    .index_or_last_known(&self.albedo)
+                    .and_then(|index| extra.1.get(index))
+                    .map(|atlas| atlas.nearest_frame(&self.albedo_offset))
+                    .unwrap_or(0);
+                MaterialPrimitive::AtlasFrame(frame)
+            }
         }
     }
 
@@ -230,7 +550,303 @@ impl AnimationSampling for Material {
         panic!("Blending is not applicable to Material animation")
     }
 
-    fn blend_method(&self, _: &Self::Channel) -> Option<BlendMethod> {
-        None
+    fn blend_method(&self, channel: &Self::Channel) -> Option<BlendMethod> {
+        match *channel {
+            MaterialChannel::AlbedoOffset
+            | MaterialChannel::EmissionOffset
+            | MaterialChannel::NormalOffset
+            | MaterialChannel::MetallicOffset
+            | MaterialChannel::RoughnessOffset
+            | MaterialChannel::AmbientOcclusionOffset
+            | MaterialChannel::CaveatOffset => Some(BlendMethod::Linear),
+            _ => None,
+        }
+    }
+}
+
+/// Keeps `MaterialTextureSet` in sync with `amethyst_assets` texture hot-reloads.
+///
+/// Reloading a `Handle<Texture>` replaces its underlying asset in place, which can
+/// leave `MaterialTextureSet`'s reverse index pointing at the texture's old identity.
+/// This system listens for `ReloadedEvent<Texture>`s and rebinds the affected index so
+/// a running material animation transparently picks up the new texture instead of
+/// `MaterialTextureSet::index` going stale.
+pub struct MaterialTextureSetReloadSystem {
+    reader: Option<ReaderId<ReloadedEvent<Texture>>>,
+}
+
+impl MaterialTextureSetReloadSystem {
+    pub fn new() -> Self {
+        MaterialTextureSetReloadSystem { reader: None }
+    }
+}
+
+impl Default for MaterialTextureSetReloadSystem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<'a> System<'a> for MaterialTextureSetReloadSystem {
+    type SystemData = (
+        FetchMut<'a, MaterialTextureSet>,
+        Fetch<'a, EventChannel<ReloadedEvent<Texture>>>,
+    );
+
+    fn run(&mut self, (mut textures, events): Self::SystemData) {
+        let reader = self
+            .reader
+            .as_mut()
+            .expect("`MaterialTextureSetReloadSystem::setup` was not called before `run`");
+        for event in events.read(reader) {
+            if let Some(index) = textures.index(&event.old_handle) {
+                textures.insert(index, event.new_handle.clone());
+            }
+        }
+    }
+
+    fn setup(&mut self, res: &mut Resources) {
+        Self::SystemData::setup(res);
+        self.reader = Some(
+            res.fetch_mut::<EventChannel<ReloadedEvent<Texture>>>()
+                .register_reader(),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use amethyst_assets::AssetStorage;
+
+    use super::*;
+
+    fn new_handle(storage: &AssetStorage<Texture>) -> Handle<Texture> {
+        storage.allocate()
+    }
+
+    #[test]
+    fn offset_add_sums_each_component() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        let b = MaterialPrimitive::Offset((0.5, 0.5), (0.5, 0.5));
+        match a.add(&b) {
+            MaterialPrimitive::Offset(u, v) => {
+                assert_eq!(u, (1.5, 2.5));
+                assert_eq!(v, (3.5, 4.5));
+            }
+            _ => panic!("expected Offset"),
+        }
+    }
+
+    #[test]
+    fn offset_sub_subtracts_each_component() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        let b = MaterialPrimitive::Offset((0.5, 0.5), (0.5, 0.5));
+        match a.sub(&b) {
+            MaterialPrimitive::Offset(u, v) => {
+                assert_eq!(u, (0.5, 1.5));
+                assert_eq!(v, (2.5, 3.5));
+            }
+            _ => panic!("expected Offset"),
+        }
+    }
+
+    #[test]
+    fn offset_mul_scales_each_component() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        match a.mul(2.0) {
+            MaterialPrimitive::Offset(u, v) => {
+                assert_eq!(u, (2.0, 4.0));
+                assert_eq!(v, (6.0, 8.0));
+            }
+            _ => panic!("expected Offset"),
+        }
+    }
+
+    #[test]
+    fn offset_dot_sums_componentwise_products() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        let b = MaterialPrimitive::Offset((2.0, 2.0), (2.0, 2.0));
+        assert_eq!(a.dot(&b), 1.0 * 2.0 + 2.0 * 2.0 + 3.0 * 2.0 + 4.0 * 2.0);
+    }
+
+    #[test]
+    fn offset_magnitude2_sums_squares_of_each_component() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        assert_eq!(a.magnitude2(), 1.0 + 4.0 + 9.0 + 16.0);
+    }
+
+    #[test]
+    fn offset_magnitude_is_sqrt_of_magnitude2() {
+        let a = MaterialPrimitive::Offset((1.0, 2.0), (3.0, 4.0));
+        assert_eq!(a.magnitude(), a.magnitude2().sqrt());
+    }
+
+    #[test]
+    fn offset_normalize_scales_to_unit_magnitude() {
+        let a = MaterialPrimitive::Offset((3.0, 0.0), (4.0, 0.0));
+        match a.normalize() {
+            MaterialPrimitive::Offset(u, v) => {
+                assert_eq!(u, (3.0 / 5.0, 0.0));
+                assert_eq!(v, (4.0 / 5.0, 0.0));
+            }
+            _ => panic!("expected Offset"),
+        }
+    }
+
+    #[test]
+    fn offset_normalize_leaves_a_near_zero_magnitude_value_untouched() {
+        let a = MaterialPrimitive::Offset((0.0, 0.0), (0.0, 0.0));
+        match a.normalize() {
+            MaterialPrimitive::Offset(u, v) => {
+                assert_eq!(u, (0.0, 0.0));
+                assert_eq!(v, (0.0, 0.0));
+            }
+            _ => panic!("expected Offset"),
+        }
+    }
+
+    #[test]
+    fn allocate_reuses_indices_freed_by_remove() {
+        let storage = AssetStorage::<Texture>::new();
+        let mut set = MaterialTextureSet::new();
+        let a = new_handle(&storage);
+        let b = new_handle(&storage);
+        let c = new_handle(&storage);
+
+        assert_eq!(set.allocate(a.clone()), 0);
+        assert_eq!(set.allocate(b.clone()), 1);
+        set.remove(0);
+
+        // The freed index 0 is handed out again before a brand new one.
+        assert_eq!(set.allocate(c.clone()), 0);
+        assert_eq!(set.index(&c), Some(0));
+        assert_eq!(set.index(&a), None);
+    }
+
+    #[test]
+    fn remove_reclaims_an_abandoned_reservation() {
+        let mut set = MaterialTextureSet::new();
+        let reserved = set.reserve();
+        set.remove(reserved);
+
+        let storage = AssetStorage::<Texture>::new();
+        let handle = new_handle(&storage);
+        assert_eq!(set.allocate(handle), reserved);
+    }
+
+    #[test]
+    fn insert_reconciles_a_manually_chosen_index_with_auto_indexing() {
+        let storage = AssetStorage::<Texture>::new();
+        let mut set = MaterialTextureSet::new();
+        let a = new_handle(&storage);
+        let b = new_handle(&storage);
+
+        // Manually bind far past what `allocate` has handed out so far.
+        set.insert(5, a.clone());
+        // `allocate` must not silently collide with the manual index.
+        assert_eq!(set.allocate(b.clone()), 0);
+        assert_eq!(set.index(&a), Some(5));
+    }
+
+    #[test]
+    fn remove_prunes_last_known_index_so_a_reused_slot_cannot_leak_into_it() {
+        let storage = AssetStorage::<Texture>::new();
+        let mut set = MaterialTextureSet::new();
+        let a = new_handle(&storage);
+        let b = new_handle(&storage);
+
+        let index = set.allocate(a.clone());
+        set.remove(index);
+        assert_eq!(set.index_or_last_known(&a), None);
+
+        // Handing the freed index to an unrelated handle must not resurrect `a`'s
+        // stale last-known mapping.
+        assert_eq!(set.allocate(b.clone()), index);
+        assert_eq!(set.index_or_last_known(&a), None);
+        assert_eq!(set.index_or_last_known(&b), Some(index));
+    }
+
+    #[test]
+    fn insert_displacing_a_handle_keeps_its_last_known_index() {
+        // Mirrors what `MaterialTextureSetReloadSystem` does: rebind an index to a
+        // new handle without an explicit `remove` of the old one.
+        let storage = AssetStorage::<Texture>::new();
+        let mut set = MaterialTextureSet::new();
+        let a = new_handle(&storage);
+        let b = new_handle(&storage);
+
+        let index = set.allocate(a.clone());
+        set.insert(index, b.clone());
+
+        assert_eq!(set.index(&a), None);
+        assert_eq!(set.index_or_last_known(&a), Some(index));
+        assert_eq!(set.index(&b), Some(index));
+    }
+
+    #[test]
+    fn remove_prunes_last_known_index_for_a_handle_displaced_earlier_by_a_reload_rebind() {
+        // a is bound, then a reload rebind displaces it in favor of b without ever
+        // calling remove(); a's sticky entry should still be pruned once the index is
+        // actually removed (as the occupant, b, is removed), not just b's.
+        let storage = AssetStorage::<Texture>::new();
+        let mut set = MaterialTextureSet::new();
+        let a = new_handle(&storage);
+        let b = new_handle(&storage);
+        let c = new_handle(&storage);
+
+        let index = set.allocate(a.clone());
+        set.insert(index, b.clone());
+        assert_eq!(set.index_or_last_known(&a), Some(index));
+
+        set.remove(index);
+        assert_eq!(set.index_or_last_known(&a), None);
+        assert_eq!(set.index_or_last_known(&b), None);
+
+        // Handing the freed index to an unrelated handle must not resurrect a's
+        // stale last-known mapping either.
+        assert_eq!(set.allocate(c.clone()), index);
+        assert_eq!(set.index_or_last_known(&a), None);
+        assert_eq!(set.index_or_last_known(&b), None);
+        assert_eq!(set.index_or_last_known(&c), Some(index));
+    }
+
+    #[test]
+    fn grid_offset_round_trips_through_nearest_frame() {
+        let atlas = AtlasGrid::Grid {
+            columns: 4,
+            rows: 3,
+        };
+        for frame in 0..12 {
+            let offset = atlas.offset(frame);
+            assert_eq!(atlas.nearest_frame(&offset), frame);
+        }
+    }
+
+    #[test]
+    fn rects_offset_round_trips_through_nearest_frame() {
+        let atlas = AtlasGrid::Rects(vec![
+            texture_offset((0.0, 0.5), (0.0, 1.0)),
+            texture_offset((0.5, 1.0), (0.0, 1.0)),
+        ]);
+        for frame in 0..2 {
+            let offset = atlas.offset(frame);
+            assert_eq!(atlas.nearest_frame(&offset), frame);
+        }
+    }
+
+    #[test]
+    fn rects_offset_wraps_out_of_range_frames() {
+        let atlas = AtlasGrid::Rects(vec![texture_offset((0.0, 1.0), (0.0, 1.0))]);
+        let offset = atlas.offset(3);
+        assert_eq!(offset.u, (0.0, 1.0));
+        assert_eq!(offset.v, (0.0, 1.0));
+    }
+
+    #[test]
+    fn empty_rects_offset_falls_back_to_identity_rect_instead_of_panicking() {
+        let atlas = AtlasGrid::Rects(Vec::new());
+        let offset = atlas.offset(3);
+        assert_eq!(offset.u, (0.0, 1.0));
+        assert_eq!(offset.v, (0.0, 1.0));
     }
 }